@@ -1,10 +1,30 @@
 use crate::fixedpoint::fixed_point_to_string;
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
+    collections::{BTreeMap, HashMap},
     fmt::Display,
 };
 
-use crate::transactions::{Transaction, TransactionIdTuple, TransactionType::*};
+use crate::transactions::{Transaction, TransactionIdTuple, TransactionType, TransactionType::*};
+
+/**
+ * Everything `execute_transaction` can reject a transaction for. Returning
+ * this instead of blindly `eprintln!`-ing lets the caller decide whether to
+ * log, count or abort (see the tally in `main`).
+ */
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum LedgerError {
+    #[error("withdrawal bigger than available funds")]
+    NotEnoughFunds,
+    #[error("referenced transaction {0} is unknown")]
+    UnknownTx(u32),
+    #[error("dispute action not allowed in the transaction's current state")]
+    DisputeNotAllowed,
+    #[error("action attempted on frozen account")]
+    FrozenAccount,
+    #[error("amount would overflow the fixed-point range")]
+    AmountOverflow,
+}
+
 /**
  * Derived default makes sense here,
  * funds all zeroed and not locked.
@@ -17,28 +37,84 @@ struct Account {
 }
 
 impl Account {
-    fn get_total_funds(&self) -> i64 {
-        return self.available_funds + self.held_funds;
+    /// Promoted to `i128` so the sum of two near-`i64::MAX` fields can never
+    /// itself overflow while being formatted.
+    fn get_total_funds(&self) -> i128 {
+        self.available_funds as i128 + self.held_funds as i128
     }
+
+    fn credit_available(&mut self, amount: i64) -> Result<(), LedgerError> {
+        self.available_funds = self
+            .available_funds
+            .checked_add(amount)
+            .ok_or(LedgerError::AmountOverflow)?;
+        Ok(())
+    }
+
+    fn debit_available(&mut self, amount: i64) -> Result<(), LedgerError> {
+        self.available_funds = self
+            .available_funds
+            .checked_sub(amount)
+            .ok_or(LedgerError::AmountOverflow)?;
+        Ok(())
+    }
+
+    fn credit_held(&mut self, amount: i64) -> Result<(), LedgerError> {
+        self.held_funds = self
+            .held_funds
+            .checked_add(amount)
+            .ok_or(LedgerError::AmountOverflow)?;
+        Ok(())
+    }
+
+    fn debit_held(&mut self, amount: i64) -> Result<(), LedgerError> {
+        self.held_funds = self
+            .held_funds
+            .checked_sub(amount)
+            .ok_or(LedgerError::AmountOverflow)?;
+        Ok(())
+    }
+}
+
+/**
+ * The lifecycle of a recorded deposit or withdrawal with respect to disputes.
+ * Every record starts `Processed`; a DISPUTE moves it to `Disputed`,
+ * from which it can only ever move to one of the two terminal states.
+ */
+#[derive(Default, PartialEq)]
+enum DisputeState {
+    #[default]
+    Processed,
+    Disputed,
+    Resolved,
+    ChargedBack,
 }
 
-struct DepositRecord {
+/**
+ * A recorded fund-moving transaction (a DEPOSIT or a WITHDRAWAL) kept so
+ * that a later DISPUTE can find it. `kind` is needed because the held-funds
+ * semantics differ between the two: disputing a deposit pulls funds out of
+ * `available` and into `held`, whereas disputing a withdrawal only puts the
+ * withdrawn amount back under `held` (the funds already left `available`).
+ */
+struct TransactionRecord {
     client: u16,
     amount: i64,
+    kind: TransactionType,
+    state: DisputeState,
 }
 
-impl TryFrom<Transaction> for DepositRecord {
+impl TryFrom<Transaction> for TransactionRecord {
     type Error = &'static str;
     fn try_from(value: Transaction) -> Result<Self, Self::Error> {
-        if let Transaction {
-            r#type: DEPOSIT,
-            client,
-            amount,
-        } = value
-        {
-            Ok(DepositRecord { client, amount })
-        } else {
-            return Err("Transaction is not a deposit");
+        match value.r#type {
+            DEPOSIT | WITHDRAWAL => Ok(TransactionRecord {
+                client: value.client,
+                amount: value.amount,
+                kind: value.r#type,
+                state: DisputeState::default(),
+            }),
+            _ => Err("Transaction is not fund-moving"),
         }
     }
 }
@@ -46,8 +122,7 @@ impl TryFrom<Transaction> for DepositRecord {
 #[derive(Default)]
 pub struct BankDatabase {
     accounts: HashMap<u16, Account>,
-    deposits: BTreeMap<u32, DepositRecord>,
-    disputes: HashSet<u32>,
+    records: BTreeMap<u32, TransactionRecord>,
 }
 
 impl Display for BankDatabase {
@@ -58,8 +133,8 @@ impl Display for BankDatabase {
                 f,
                 "{},{},{},{},{}\n",
                 client,
-                fixed_point_to_string(account.available_funds),
-                fixed_point_to_string(account.held_funds),
+                fixed_point_to_string(account.available_funds as i128),
+                fixed_point_to_string(account.held_funds as i128),
                 fixed_point_to_string(account.get_total_funds()),
                 account.locked
             )?;
@@ -69,87 +144,169 @@ impl Display for BankDatabase {
 }
 
 impl BankDatabase {
-    pub fn execute_transaction(&mut self, transaction_id_tuple: TransactionIdTuple) {
+    pub fn execute_transaction(
+        &mut self,
+        transaction_id_tuple: TransactionIdTuple,
+    ) -> Result<(), LedgerError> {
         let transaction_id = transaction_id_tuple.0;
         let transaction = transaction_id_tuple.1;
 
         match transaction.r#type {
             DEPOSIT => {
-                let mut transaction_account = self.accounts.entry(transaction.client).or_default();
-                transaction_account.available_funds += transaction.amount;
-                self.deposits.insert(
+                let transaction_account = self.accounts.entry(transaction.client).or_default();
+                transaction_account.credit_available(transaction.amount)?;
+                self.records.insert(
                     transaction_id,
                     transaction
                         .try_into()
-                        .expect("This only fails if transaction is not a deposit"),
+                        .expect("This only fails if transaction is not fund-moving"),
                 );
             }
             WITHDRAWAL => {
-                let mut transaction_account = self.accounts.entry(transaction.client).or_default();
+                let transaction_account = self.accounts.entry(transaction.client).or_default();
                 if transaction_account.locked {
-                    eprintln!("Error: Withdrawal attempted on frozen account");
-                    return;
+                    return Err(LedgerError::FrozenAccount);
                 } else if transaction_account.available_funds < transaction.amount {
-                    eprintln!("Error: withdrawal bigger than available funds");
-                    return;
+                    return Err(LedgerError::NotEnoughFunds);
                 } else {
-                    transaction_account.available_funds -= transaction.amount;
+                    transaction_account.debit_available(transaction.amount)?;
+                    self.records.insert(
+                        transaction_id,
+                        transaction
+                            .try_into()
+                            .expect("This only fails if transaction is not fund-moving"),
+                    );
                 }
             }
-            DISPUTE => {
-                if self.disputes.contains(&transaction_id) {
-                    eprintln!("Warning: Dropped duplicate dispute claim");
-                    return;
+            DISPUTE => match self.records.get_mut(&transaction_id) {
+                Some(record) if record.state == DisputeState::Processed => {
+                    let (client, amount, kind) = (record.client, record.amount, record.kind);
+                    record.state = DisputeState::Disputed;
+                    let account = self
+                        .accounts
+                        .get_mut(&client)
+                        .expect("Existence proven by transaction record");
+                    // Both kinds hold the amount; only a deposit also frees it
+                    // from `available` (a withdrawal already spent it).
+                    account.credit_held(amount)?;
+                    if let DEPOSIT = kind {
+                        account.debit_available(amount)?;
+                    }
                 }
-                if let Some(disputed_deposit) = self.deposits.get(&transaction_id) {
-                    let deposit_account = self
+                Some(_) => return Err(LedgerError::DisputeNotAllowed),
+                None => return Err(LedgerError::UnknownTx(transaction_id)),
+            },
+            RESOLVE => match self.records.get_mut(&transaction_id) {
+                Some(record) if record.state == DisputeState::Disputed => {
+                    let (client, amount, kind) = (record.client, record.amount, record.kind);
+                    record.state = DisputeState::Resolved;
+                    let account = self
                         .accounts
-                        .get_mut(&disputed_deposit.client)
-                        .expect("Existence proven by deposit record");
-                    deposit_account.available_funds -= disputed_deposit.amount;
-                    deposit_account.held_funds += disputed_deposit.amount;
-                    self.disputes.insert(transaction_id);
-                } else {
-                    eprintln!("Warning: Dropped dispute with invalid tx id")
+                        .get_mut(&client)
+                        .expect("Existence proven by transaction record");
+                    account.debit_held(amount)?;
+                    if let DEPOSIT = kind {
+                        account.credit_available(amount)?;
+                    }
                 }
-            }
-            RESOLVE => {
-                if !self.disputes.contains(&transaction_id) {
-                    eprintln!("Warning: Dropped dispute resolve with undisputed tx");
-                    return;
+                Some(_) => return Err(LedgerError::DisputeNotAllowed),
+                None => return Err(LedgerError::UnknownTx(transaction_id)),
+            },
+            CHARGEBACK => match self.records.get_mut(&transaction_id) {
+                Some(record) if record.state == DisputeState::Disputed => {
+                    let (client, amount, kind) = (record.client, record.amount, record.kind);
+                    record.state = DisputeState::ChargedBack;
+                    let account = self
+                        .accounts
+                        .get_mut(&client)
+                        .expect("Existence proven by transaction record");
+                    account.debit_held(amount)?;
+                    // A reversed withdrawal returns the funds to the client;
+                    // a reversed deposit removes them for good.
+                    if let WITHDRAWAL = kind {
+                        account.credit_available(amount)?;
+                    }
+                    account.locked = true;
                 }
-                let disputed_deposit = self.deposits.get(&transaction_id)
-                    .expect("That the dispute is contained in disputes implies that the deposit record exists");
-                let deposit_account = self
-                    .accounts
-                    .get_mut(&disputed_deposit.client)
-                    .expect("Existence proven by deposit record");
-                deposit_account.held_funds -= disputed_deposit.amount;
-                deposit_account.available_funds += disputed_deposit.amount;
-                self.disputes.remove(&transaction_id);
+                Some(_) => return Err(LedgerError::DisputeNotAllowed),
+                None => return Err(LedgerError::UnknownTx(transaction_id)),
+            },
+        }
+        Ok(())
+    }
+
+    /**
+     * Process the stream across `shards` worker threads, partitioning by the
+     * owning client so each worker owns a disjoint set of clients. A dispute
+     * is applied to the account of the *referenced* record's owner, not to the
+     * client named on the dispute row (which the engine ignores), so routing
+     * by the row's own client could send a dispute to a worker that has never
+     * seen the disputed tx. We therefore first resolve each fund-moving tx's
+     * owner and route dispute/resolve/chargeback rows to that owner's shard,
+     * keeping every event for a client on one worker. The per-worker account
+     * maps are merged at the end (their key sets are disjoint, so no client
+     * can appear twice).
+     */
+    pub fn process_sharded(
+        transactions: impl Iterator<Item = TransactionIdTuple>,
+        shards: usize,
+    ) -> Self {
+        let shards = shards.max(1);
+        let transactions: Vec<TransactionIdTuple> = transactions.collect();
+
+        // Which client owns each fund-moving tx, so disputes can be routed to
+        // the same shard as the record they reference.
+        let mut owner: HashMap<u32, u16> = HashMap::new();
+        for (transaction_id, transaction) in &transactions {
+            if let DEPOSIT | WITHDRAWAL = transaction.r#type {
+                owner.insert(*transaction_id, transaction.client);
             }
-            CHARGEBACK => {
-                if !self.disputes.contains(&transaction_id) {
-                    eprintln!("Warning: Dropped chargeback with undisputed tx");
-                    return;
+        }
+
+        let mut buckets: Vec<Vec<TransactionIdTuple>> =
+            (0..shards).map(|_| Vec::new()).collect();
+        for transaction in transactions {
+            let (transaction_id, body) = transaction;
+            let routing_client = match body.r#type {
+                DEPOSIT | WITHDRAWAL => body.client,
+                // Fall back to the row's client for an unknown tx; it is
+                // dropped as UnknownTx on whichever shard it lands, matching
+                // the serial engine.
+                DISPUTE | RESOLVE | CHARGEBACK => {
+                    *owner.get(&transaction_id).unwrap_or(&body.client)
                 }
-                let disputed_deposit = self.deposits.get(&transaction_id)
-                    .expect("That the dispute is contained in disputes implies that the deposit record exists");
-                let deposit_account = self
+            };
+            buckets[routing_client as usize % shards].push(transaction);
+        }
+
+        let mut merged = BankDatabase::default();
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = buckets
+                .into_iter()
+                .map(|bucket| {
+                    scope.spawn(move || {
+                        let mut database = BankDatabase::default();
+                        for transaction in bucket {
+                            // Dropped transactions are not tallied per worker;
+                            // only the final merged account table is reported.
+                            let _ = database.execute_transaction(transaction);
+                        }
+                        database.accounts
+                    })
+                })
+                .collect();
+            for worker in workers {
+                merged
                     .accounts
-                    .get_mut(&disputed_deposit.client)
-                    .expect("Existence proven by deposit record");
-                deposit_account.held_funds -= disputed_deposit.amount;
-                deposit_account.locked = true;
-                self.disputes.remove(&transaction_id);
+                    .extend(worker.join().expect("sharding worker panicked"));
             }
-        }
-        // Create account from transaction if it does not exist
-        self.accounts.entry(transaction.client).or_default();
+        });
+        merged
     }
 }
 
 #[cfg(test)]
+#[allow(unused_must_use)]
 mod tests {
     use super::*;
 
@@ -245,7 +402,7 @@ mod tests {
             r#type: DISPUTE,
         },
     );
-    const DISPUTE_NON_DEPOSIT: TransactionIdTuple = (
+    const DISPUTE_WITHDRAWAL: TransactionIdTuple = (
         3,
         Transaction {
             amount: DONT_CARE,
@@ -253,6 +410,22 @@ mod tests {
             r#type: DISPUTE,
         },
     );
+    const RESOLVE_WITHDRAWAL: TransactionIdTuple = (
+        3,
+        Transaction {
+            amount: DONT_CARE,
+            client: CLIENT_DONT_CARE,
+            r#type: RESOLVE,
+        },
+    );
+    const CHARGEBACK_WITHDRAWAL: TransactionIdTuple = (
+        3,
+        Transaction {
+            amount: DONT_CARE,
+            client: CLIENT_DONT_CARE,
+            r#type: CHARGEBACK,
+        },
+    );
     mod disputes {
         use super::*;
 
@@ -274,17 +447,47 @@ mod tests {
             assert_eq!(bank.accounts.get(&CLIENT_1).unwrap().held_funds, 0);
             assert_eq!(bank.accounts.get(&CLIENT_1).unwrap().available_funds, 50);
         }
+        /// Disputing a withdrawal moves the withdrawn amount under hold without
+        /// touching `available` (the funds already left on withdrawal), so held
+        /// can legitimately exceed available for the duration of the dispute.
         #[test]
-        fn dispute_non_deposit_is_ignored() {
+        fn dispute_withdrawal_holds_amount() {
             let mut bank = BankDatabase::default();
             bank.execute_transaction(GIVE_100_CLIENT_1);
             bank.execute_transaction(GIVE_50_CLIENT_1);
             bank.execute_transaction(REMOVE_100_CLIENT_1);
-            bank.execute_transaction(DISPUTE_NON_DEPOSIT);
+            bank.execute_transaction(DISPUTE_WITHDRAWAL);
+
+            assert_eq!(bank.accounts.get(&CLIENT_1).unwrap().held_funds, 100);
+            assert_eq!(bank.accounts.get(&CLIENT_1).unwrap().available_funds, 50);
+        }
+        #[test]
+        fn resolve_withdrawal_releases_hold() {
+            let mut bank = BankDatabase::default();
+            bank.execute_transaction(GIVE_100_CLIENT_1);
+            bank.execute_transaction(GIVE_50_CLIENT_1);
+            bank.execute_transaction(REMOVE_100_CLIENT_1);
+            bank.execute_transaction(DISPUTE_WITHDRAWAL);
+            bank.execute_transaction(RESOLVE_WITHDRAWAL);
 
             assert_eq!(bank.accounts.get(&CLIENT_1).unwrap().held_funds, 0);
             assert_eq!(bank.accounts.get(&CLIENT_1).unwrap().available_funds, 50);
         }
+        /// A charged-back withdrawal is reversed: the held amount returns to
+        /// `available` and the account is frozen.
+        #[test]
+        fn chargeback_withdrawal_returns_funds() {
+            let mut bank = BankDatabase::default();
+            bank.execute_transaction(GIVE_100_CLIENT_1);
+            bank.execute_transaction(GIVE_50_CLIENT_1);
+            bank.execute_transaction(REMOVE_100_CLIENT_1);
+            bank.execute_transaction(DISPUTE_WITHDRAWAL);
+            bank.execute_transaction(CHARGEBACK_WITHDRAWAL);
+
+            assert_eq!(bank.accounts.get(&CLIENT_1).unwrap().held_funds, 0);
+            assert_eq!(bank.accounts.get(&CLIENT_1).unwrap().available_funds, 150);
+            assert_eq!(bank.accounts.get(&CLIENT_1).unwrap().locked, true);
+        }
         #[test]
         fn dispute_duplicate_is_ignored() {
             let mut bank = BankDatabase::default();
@@ -295,6 +498,29 @@ mod tests {
             assert_eq!(bank.accounts.get(&CLIENT_1).unwrap().held_funds, 50);
             assert_eq!(bank.accounts.get(&CLIENT_1).unwrap().available_funds, 0);
         }
+        #[test]
+        fn dispute_after_resolve_is_ignored() {
+            let mut bank = BankDatabase::default();
+            bank.execute_transaction(GIVE_50_CLIENT_1);
+            bank.execute_transaction(DISPUTE_1);
+            bank.execute_transaction(RESOLVE_1);
+            bank.execute_transaction(DISPUTE_1);
+
+            assert_eq!(bank.accounts.get(&CLIENT_1).unwrap().held_funds, 0);
+            assert_eq!(bank.accounts.get(&CLIENT_1).unwrap().available_funds, 50);
+        }
+        #[test]
+        fn dispute_after_chargeback_is_ignored() {
+            let mut bank = BankDatabase::default();
+            bank.execute_transaction(GIVE_50_CLIENT_1);
+            bank.execute_transaction(DISPUTE_1);
+            bank.execute_transaction(CHARGEBACK_1);
+            bank.execute_transaction(DISPUTE_1);
+
+            assert_eq!(bank.accounts.get(&CLIENT_1).unwrap().held_funds, 0);
+            assert_eq!(bank.accounts.get(&CLIENT_1).unwrap().available_funds, 0);
+            assert_eq!(bank.accounts.get(&CLIENT_1).unwrap().locked, true);
+        }
     }
 
     const RESOLVE_1: TransactionIdTuple = (
@@ -395,4 +621,56 @@ mod tests {
             assert_eq!(bank.accounts.get(&CLIENT_1).unwrap().locked, false);
         }
     }
+
+    mod sharding {
+        use super::*;
+
+        const GIVE_70_CLIENT_2: TransactionIdTuple = (
+            4,
+            Transaction {
+                amount: 70,
+                client: 11,
+                r#type: DEPOSIT,
+            },
+        );
+
+        /// A client's account state must be independent of whether the engine
+        /// ran serially or sharded it onto a worker thread.
+        #[test]
+        fn parallel_matches_serial() {
+            let transactions = [
+                GIVE_50_CLIENT_1,
+                GIVE_100_CLIENT_1,
+                GIVE_70_CLIENT_2,
+                DISPUTE_1,
+                REMOVE_100_CLIENT_1,
+                RESOLVE_1,
+            ];
+
+            let mut serial = BankDatabase::default();
+            for transaction in transactions {
+                serial.execute_transaction(transaction);
+            }
+            let parallel = BankDatabase::process_sharded(transactions.into_iter(), 4);
+
+            let snapshot = |bank: &BankDatabase| {
+                let mut rows: Vec<_> = bank
+                    .accounts
+                    .iter()
+                    .map(|(client, account)| {
+                        (
+                            *client,
+                            account.available_funds,
+                            account.held_funds,
+                            account.locked,
+                        )
+                    })
+                    .collect();
+                rows.sort();
+                rows
+            };
+
+            assert_eq!(snapshot(&serial), snapshot(&parallel));
+        }
+    }
 }
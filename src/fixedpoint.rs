@@ -20,44 +20,73 @@
  */
 const FIXED_POINT_MAGNITUDE: i64 = 10000;
 
-pub fn string_to_fixed_point(string: &str) -> Result<i64, &'static str> {
+/**
+ * Everything that can go wrong while turning a decimal string into a
+ * fixed-point amount. Carrying a dedicated enum rather than the old
+ * `&'static str` lets callers report a specific reason (and matches the
+ * crate's "validate at parse time" stance, see Note 2 above).
+ */
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum ParseError {
+    #[error("amount field is missing")]
+    MissingAmount,
+    #[error("amount is negative")]
+    NegativeAmount,
+    #[error("amount exceeds the asserted 4 digits of fixed-point precision")]
+    TooPreciseAmount,
+    #[error("amount is malformed")]
+    MalformedAmount,
+    #[error("amount is outside the representable fixed-point range")]
+    OutOfRange,
+}
+
+pub fn string_to_fixed_point(string: &str) -> Result<i64, ParseError> {
     let split_amount: Vec<&str> = string.split(".").collect();
 
     if split_amount.len() != 2 {
-        return Err("Amount contains more than one dot or less than one dot");
+        return Err(ParseError::MalformedAmount);
     }
 
-    let units: i64 = split_amount[0].parse().expect("Couldn't parse amount");
+    let units: i64 = split_amount[0]
+        .parse()
+        .map_err(|_| ParseError::MalformedAmount)?;
     if units < 0 {
-        return Err("Invalid amount: negative");
+        return Err(ParseError::NegativeAmount);
     }
 
     const EXPECTED_PRECISION: usize = 4;
     let digits = split_amount[1].len();
 
     if digits > EXPECTED_PRECISION {
-        return Err("Provided amount exceeds asserted 4 digits past point fixed point precision");
+        return Err(ParseError::TooPreciseAmount);
     }
 
     let decimal_multiplier: i64 = 10i64.pow((EXPECTED_PRECISION - digits).try_into().unwrap());
 
     let mut ten_thousandths: i64 = split_amount[1]
         .parse()
-        .expect("Couldnt parse ten thousandths");
+        .map_err(|_| ParseError::MalformedAmount)?;
 
     ten_thousandths *= decimal_multiplier;
 
-    Ok(units * FIXED_POINT_MAGNITUDE + ten_thousandths)
+    // Reject magnitudes that don't fit the fixed-point range here, so an
+    // out-of-range amount is caught in isolation at parse time rather than
+    // silently wrapping once it reaches a balance.
+    units
+        .checked_mul(FIXED_POINT_MAGNITUDE)
+        .and_then(|units| units.checked_add(ten_thousandths))
+        .ok_or(ParseError::OutOfRange)
 }
 
 /**
  * I can safely ignore negatives as they should be rejected at parse time
  */
-pub fn fixed_point_to_string(fixed_point: i64) -> String {
+pub fn fixed_point_to_string(fixed_point: i128) -> String {
+    let magnitude = FIXED_POINT_MAGNITUDE as i128;
     format!(
         "{}.{:4>0}",
-        fixed_point / FIXED_POINT_MAGNITUDE,
-        fixed_point.abs() % FIXED_POINT_MAGNITUDE
+        fixed_point / magnitude,
+        fixed_point.abs() % magnitude
     )
 }
 
@@ -107,14 +136,19 @@ mod tests {
             assert!(string_to_fixed_point("-1.010").is_err());
         }
         #[test]
-        #[should_panic]
+        fn rejects_out_of_range_magnitude() {
+            assert_eq!(
+                string_to_fixed_point("92233720368547758.0"),
+                Err(ParseError::OutOfRange)
+            );
+        }
+        #[test]
         fn badly_formatted_non_number_decimal() {
-            string_to_fixed_point("1.0a10");
+            assert_eq!(string_to_fixed_point("1.0a10"), Err(ParseError::MalformedAmount));
         }
         #[test]
-        #[should_panic]
         fn badly_formatted_non_number_integer() {
-            string_to_fixed_point("1a.010");
+            assert_eq!(string_to_fixed_point("1a.010"), Err(ParseError::MalformedAmount));
         }
     }
 
@@ -3,6 +3,8 @@ mod fixedpoint;
 mod transactions;
 
 use clap::Parser;
+use std::fs::File;
+use std::io::{self, BufReader};
 use std::path::PathBuf;
 use transactions::csv_to_transaction_iterator;
 
@@ -10,16 +12,48 @@ use crate::bank::BankDatabase;
 
 #[derive(Parser, Debug)]
 struct Args {
-    transactions_filepath: PathBuf,
+    /// Path to the transactions CSV. Omit it, or pass `-`, to read from stdin
+    /// so the engine can sit in a pipeline. The serial mode reads the input
+    /// row by row, but every deposit and withdrawal is kept for later
+    /// disputes, so memory grows with the input; `--parallel` reads the whole
+    /// input into memory before processing it.
+    transactions_filepath: Option<PathBuf>,
+    /// Shard the stream by client id across worker threads instead of
+    /// processing serially on one thread.
+    #[arg(long)]
+    parallel: bool,
 }
 
 fn main() {
-    let transactions_filepath = Args::parse().transactions_filepath;
-    let mut bank = BankDatabase::default();
+    let args = Args::parse();
 
-    for transaction in csv_to_transaction_iterator(transactions_filepath) {
-        bank.execute_transaction(transaction);
-    }
+    let source: Box<dyn io::Read> = match args.transactions_filepath {
+        Some(path) if path.as_os_str() != "-" => {
+            Box::new(File::open(path).expect("Cannot read from file"))
+        }
+        _ => Box::new(io::stdin()),
+    };
+    let transactions = csv_to_transaction_iterator(BufReader::new(source));
+
+    let bank = if args.parallel {
+        let shards = std::thread::available_parallelism()
+            .map(|cores| cores.get())
+            .unwrap_or(1);
+        BankDatabase::process_sharded(transactions, shards)
+    } else {
+        let mut bank = BankDatabase::default();
+        let mut dropped = 0usize;
+        for transaction in transactions {
+            if let Err(error) = bank.execute_transaction(transaction) {
+                dropped += 1;
+                eprintln!("Warning: dropped transaction: {}", error);
+            }
+        }
+        if dropped > 0 {
+            eprintln!("Dropped {} transaction(s) during processing", dropped);
+        }
+        bank
+    };
 
     println!("{}", bank);
 }
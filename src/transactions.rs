@@ -1,8 +1,8 @@
-use std::path::PathBuf;
+use std::io;
 
 use serde::Deserialize;
 
-use crate::fixedpoint::string_to_fixed_point;
+use crate::fixedpoint::{string_to_fixed_point, ParseError};
 
 #[derive(Debug, Deserialize, Copy, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -19,7 +19,9 @@ struct DeserializedTransaction {
     r#type: TransactionType,
     client: u16,
     tx: u32,
-    amount: String,
+    // Dispute/resolve/chargeback rows legitimately carry no amount, so the
+    // column may be empty or omitted entirely (see `.flexible(true)` below).
+    amount: Option<String>,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -32,9 +34,19 @@ pub struct Transaction {
 pub type TransactionIdTuple = (u32, Transaction);
 
 impl TryFrom<DeserializedTransaction> for TransactionIdTuple {
-    type Error = &'static str;
+    type Error = ParseError;
     fn try_from(deserialized: DeserializedTransaction) -> Result<Self, Self::Error> {
-        let amount = string_to_fixed_point(&deserialized.amount)?;
+        // Treat an empty field the same as an omitted one.
+        let amount_field = deserialized.amount.filter(|amount| !amount.is_empty());
+        let amount = match deserialized.r#type {
+            TransactionType::DEPOSIT | TransactionType::WITHDRAWAL => {
+                string_to_fixed_point(&amount_field.ok_or(ParseError::MissingAmount)?)?
+            }
+            // The amount is meaningless for these; the referenced tx carries it.
+            TransactionType::DISPUTE
+            | TransactionType::RESOLVE
+            | TransactionType::CHARGEBACK => 0,
+        };
         Ok((
             deserialized.tx,
             Transaction {
@@ -46,12 +58,14 @@ impl TryFrom<DeserializedTransaction> for TransactionIdTuple {
     }
 }
 
-pub fn csv_to_transaction_iterator(path: PathBuf) -> impl Iterator<Item = TransactionIdTuple> {
+pub fn csv_to_transaction_iterator<R: io::Read>(
+    source: R,
+) -> impl Iterator<Item = TransactionIdTuple> {
     let reader = csv::ReaderBuilder::new()
         .has_headers(true)
         .trim(csv::Trim::All)
-        .from_path(path)
-        .expect("Cannot read from file");
+        .flexible(true)
+        .from_reader(source);
 
     reader
         .into_deserialize()